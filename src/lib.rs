@@ -1,10 +1,14 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 
-use embedded_hal::{blocking::i2c, prelude::*, timer};
+use embedded_hal::{blocking::i2c, timer};
+#[cfg(feature = "float")]
+use libm::logf;
+use nb::block;
 
 const DEVICE_ADDRESS: i2c::SevenBitAddress = 0xB8;
 
 const FUNC_READ_REGISTERS: u8 = 0x03;
+const FUNC_WRITE_REGISTERS: u8 = 0x10;
 
 const REG_HUMIDITY_HIGH: u8 = 0;
 const REG_HUMIDITY_LOW: u8 = 1;
@@ -12,64 +16,342 @@ const REG_TEMPERATURE_HIGH: u8 = 2;
 const REG_TEMPERATURE_LOW: u8 = 3;
 const MEASUREMENT_REG_COUNT: u8 = 4;
 
+const REG_MODEL_HIGH: u8 = 0x08;
+const DEVICE_INFO_REG_COUNT: u8 = 7;
+
+const REG_USER_REGISTERS_START: u8 = 0x10;
+const USER_REGISTER_COUNT: u8 = 4;
+
+/// Upper bound on the number of registers a single [`read_registers`] call
+/// can read in one transaction, sized to the AM2320's full register map
+/// (0x00-0x13). The scratch buffer shared by every read goes on the stack,
+/// so this also bounds that buffer's size.
+pub const MAX_REGISTER_COUNT: usize = 0x14;
+
 pub enum I2cError<I: i2c::WriteRead + i2c::Write> {
     Write(<I as i2c::Write>::Error),
     WriteRead(<I as i2c::WriteRead>::Error),
 }
 
+// `derive(Debug)` would bound `I: Debug` instead of bounding the associated
+// error types that actually appear in the fields, so the bounds are written
+// out by hand here.
+impl<I> core::fmt::Debug for I2cError<I>
+where
+    I: i2c::WriteRead + i2c::Write,
+    <I as i2c::Write>::Error: core::fmt::Debug,
+    <I as i2c::WriteRead>::Error: core::fmt::Debug,
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Write(e) => f.debug_tuple("Write").field(e).finish(),
+            Self::WriteRead(e) => f.debug_tuple("WriteRead").field(e).finish(),
+        }
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl<I> defmt::Format for I2cError<I>
+where
+    I: i2c::WriteRead + i2c::Write,
+    <I as i2c::Write>::Error: defmt::Format,
+    <I as i2c::WriteRead>::Error: defmt::Format,
+{
+    fn format(&self, f: defmt::Formatter) {
+        match self {
+            Self::Write(e) => defmt::write!(f, "Write({})", e),
+            Self::WriteRead(e) => defmt::write!(f, "WriteRead({})", e),
+        }
+    }
+}
+
 pub enum Error<I: i2c::WriteRead + i2c::Write> {
     SensorFailed,
     IncorrectCrc,
     I2cError(I2cError<I>),
 }
 
-pub fn measure<I, T>(i2c: &mut I, timer: &mut T) -> Result<Measurement, Error<I>>
+// See the note on `I2cError`'s `Debug` impl above for why this isn't derived.
+impl<I> core::fmt::Debug for Error<I>
+where
+    I: i2c::WriteRead + i2c::Write,
+    <I as i2c::Write>::Error: core::fmt::Debug,
+    <I as i2c::WriteRead>::Error: core::fmt::Debug,
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::SensorFailed => f.debug_struct("SensorFailed").finish(),
+            Self::IncorrectCrc => f.debug_struct("IncorrectCrc").finish(),
+            Self::I2cError(e) => f.debug_tuple("I2cError").field(e).finish(),
+        }
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl<I> defmt::Format for Error<I>
+where
+    I: i2c::WriteRead + i2c::Write,
+    <I as i2c::Write>::Error: defmt::Format,
+    <I as i2c::WriteRead>::Error: defmt::Format,
+{
+    fn format(&self, f: defmt::Formatter) {
+        match self {
+            Self::SensorFailed => defmt::write!(f, "SensorFailed"),
+            Self::IncorrectCrc => defmt::write!(f, "IncorrectCrc"),
+            Self::I2cError(e) => defmt::write!(f, "I2cError({})", e),
+        }
+    }
+}
+
+/// Driver for the AM2320 temperature and humidity sensor.
+///
+/// Owns the I2C bus and the timer used to satisfy the sensor's wake-up
+/// timing, so a single instance can be stored and reused for repeated
+/// measurements instead of threading both peripherals through a free
+/// function on every call.
+pub struct Am2320<I, T>
+where
+    T: timer::CountDown,
+{
+    i2c: I,
+    timer: T,
+    wake_delay: T::Time,
+}
+
+impl<I, T> Am2320<I, T>
 where
     I: i2c::WriteRead + i2c::Write,
     T: timer::CountDown,
+    T::Time: Clone,
 {
-    const COMMAND: [u8; 3] = [
-        FUNC_READ_REGISTERS,
-        REG_HUMIDITY_HIGH,
-        MEASUREMENT_REG_COUNT,
-    ];
+    /// Creates a new driver from the given I2C bus and timer.
+    ///
+    /// `wake_delay` is the time to wait after writing the wake-up byte before
+    /// the sensor is polled for a measurement. The AM2320 datasheet requires
+    /// waiting more than 800 µs and less than 3 ms after waking it up, so
+    /// pick a value comfortably inside that window.
+    pub fn new(i2c: I, timer: T, wake_delay: T::Time) -> Self {
+        Self {
+            i2c,
+            timer,
+            wake_delay,
+        }
+    }
 
+    /// Reads a measurement from the sensor.
+    pub fn measure(&mut self) -> Result<Measurement, Error<I>> {
+        measure(&mut self.i2c, &mut self.timer, self.wake_delay.clone())
+    }
+
+    /// Reads the sensor's model number, firmware version and device ID.
+    pub fn device_info(&mut self) -> Result<DeviceInfo, Error<I>> {
+        device_info(&mut self.i2c, &mut self.timer, self.wake_delay.clone())
+    }
+
+    /// Writes the two writable user registers (0x10-0x13).
+    pub fn write_user_registers(
+        &mut self,
+        data: [u8; USER_REGISTER_COUNT as usize],
+    ) -> Result<(), Error<I>> {
+        write_user_registers(
+            &mut self.i2c,
+            &mut self.timer,
+            self.wake_delay.clone(),
+            data,
+        )
+    }
+
+    /// Reads `out.len()` registers starting at `start_reg`, for registers not
+    /// covered by [`Am2320::measure`] or [`Am2320::device_info`] (e.g. the
+    /// status register at 0x0F). See [`read_registers`] for the bound on
+    /// `out.len()`.
+    pub fn read_registers(&mut self, start_reg: u8, out: &mut [u8]) -> Result<(), Error<I>> {
+        read_registers(
+            &mut self.i2c,
+            &mut self.timer,
+            self.wake_delay.clone(),
+            start_reg,
+            out,
+        )
+    }
+
+    /// Releases the I2C bus and timer back to the caller.
+    pub fn destroy(self) -> (I, T) {
+        (self.i2c, self.timer)
+    }
+}
+
+/// Reads a measurement from the sensor over the given I2C bus.
+///
+/// `wake_delay` is the time to wait after writing the wake-up byte before
+/// polling the sensor for a measurement; see [`Am2320::new`] for the
+/// constraints the datasheet places on this value.
+///
+/// Prefer [`Am2320`] when holding the bus and timer alongside the driver is
+/// an option; this free function is kept for callers that only need a single
+/// one-off reading.
+pub fn measure<I, T>(
+    i2c: &mut I,
+    timer: &mut T,
+    wake_delay: T::Time,
+) -> Result<Measurement, Error<I>>
+where
+    I: i2c::WriteRead + i2c::Write,
+    T: timer::CountDown,
+{
+    let mut data = [0; MEASUREMENT_REG_COUNT as usize];
+    read_registers(i2c, timer, wake_delay, REG_HUMIDITY_HIGH, &mut data)?;
+
+    let raw_humidity = u16::from_be_bytes([
+        data[REG_HUMIDITY_HIGH as usize],
+        data[REG_HUMIDITY_LOW as usize],
+    ]);
+    let raw_temperature = u16::from_be_bytes([
+        data[REG_TEMPERATURE_HIGH as usize],
+        data[REG_TEMPERATURE_LOW as usize],
+    ]);
+
+    Ok(Measurement::from_raw(raw_temperature, raw_humidity))
+}
+
+/// Reads the sensor's model number, firmware version and device ID over the
+/// given I2C bus.
+///
+/// Prefer [`Am2320::device_info`] when holding the bus and timer alongside
+/// the driver is an option.
+pub fn device_info<I, T>(
+    i2c: &mut I,
+    timer: &mut T,
+    wake_delay: T::Time,
+) -> Result<DeviceInfo, Error<I>>
+where
+    I: i2c::WriteRead + i2c::Write,
+    T: timer::CountDown,
+{
+    let mut data = [0; DEVICE_INFO_REG_COUNT as usize];
+    read_registers(i2c, timer, wake_delay, REG_MODEL_HIGH, &mut data)?;
+
+    Ok(DeviceInfo {
+        model: u16::from_be_bytes([data[0], data[1]]),
+        version: data[2],
+        device_id: u32::from_be_bytes([data[3], data[4], data[5], data[6]]),
+    })
+}
+
+/// Writes the two writable user registers (0x10-0x13) over the given I2C
+/// bus.
+///
+/// Prefer [`Am2320::write_user_registers`] when holding the bus and timer
+/// alongside the driver is an option.
+pub fn write_user_registers<I, T>(
+    i2c: &mut I,
+    timer: &mut T,
+    wake_delay: T::Time,
+    data: [u8; USER_REGISTER_COUNT as usize],
+) -> Result<(), Error<I>>
+where
+    I: i2c::WriteRead + i2c::Write,
+    T: timer::CountDown,
+{
     // This write wakes up the sensor.
     i2c.write(DEVICE_ADDRESS, &[0x00])
         .map_err(|e| Error::I2cError(I2cError::Write(e)))?;
 
-    let mut buf = [0; 8];
+    timer.start(wake_delay);
+    block!(timer.wait()).unwrap();
+
+    let mut command = [0; 3 + USER_REGISTER_COUNT as usize + 2];
+    command[0] = FUNC_WRITE_REGISTERS;
+    command[1] = REG_USER_REGISTERS_START;
+    command[2] = USER_REGISTER_COUNT;
+    command[3..3 + data.len()].copy_from_slice(&data);
+    let crc = crc16(&command[..3 + data.len()]).to_be_bytes();
+    command[3 + data.len()..].copy_from_slice(&crc);
+
+    // The write response echoes function code, start register and count,
+    // same as a Modbus write response, followed by its own CRC16.
+    let mut resp = [0; 5];
+    i2c.write_read(DEVICE_ADDRESS, &command, &mut resp)
+        .map_err(|e| Error::I2cError(I2cError::WriteRead(e)))?;
+
+    if resp[0] != FUNC_WRITE_REGISTERS
+        || resp[1] != REG_USER_REGISTERS_START
+        || resp[2] != USER_REGISTER_COUNT
+    {
+        return Err(Error::SensorFailed);
+    }
+
+    let crc = u16::from_be_bytes([resp[3], resp[4]]);
+    if crc != crc16(&resp[..3]) {
+        return Err(Error::IncorrectCrc);
+    }
+
+    Ok(())
+}
+
+/// Reads `out.len()` registers starting at `start_reg` over the given I2C
+/// bus, validating the response's function code, length and CRC16.
+///
+/// This is the general escape hatch for registers not covered by a typed
+/// helper like [`measure`] or [`device_info`] (e.g. the status register at
+/// 0x0F). `out.len()` must not exceed [`MAX_REGISTER_COUNT`].
+///
+/// Prefer [`Am2320::read_registers`] when holding the bus and timer
+/// alongside the driver is an option.
+pub fn read_registers<I, T>(
+    i2c: &mut I,
+    timer: &mut T,
+    wake_delay: T::Time,
+    start_reg: u8,
+    out: &mut [u8],
+) -> Result<(), Error<I>>
+where
+    I: i2c::WriteRead + i2c::Write,
+    T: timer::CountDown,
+{
+    if out.len() > MAX_REGISTER_COUNT {
+        return Err(Error::SensorFailed);
+    }
+
+    let count = out.len() as u8;
+    let command = [FUNC_READ_REGISTERS, start_reg, count];
+
+    // This write wakes up the sensor.
+    i2c.write(DEVICE_ADDRESS, &[0x00])
+        .map_err(|e| Error::I2cError(I2cError::Write(e)))?;
+
+    // The sensor NACKs reads that arrive too soon after waking up, so wait
+    // out the required delay before talking to it again.
+    timer.start(wake_delay);
+    block!(timer.wait()).unwrap();
+
     // cannot use From impl because there is no way (that i know of)
     // to enforce that the associated error type is not our Error enum.
     // which is necessary because otherwise there is a conflicting trait impl.
-    i2c.write_read(DEVICE_ADDRESS, &COMMAND, &mut buf)
+    let mut buf = [0; 2 + MAX_REGISTER_COUNT + 2];
+    let buf = &mut buf[..2 + out.len() + 2];
+    i2c.write_read(DEVICE_ADDRESS, &command, buf)
         .map_err(|e| Error::I2cError(I2cError::WriteRead(e)))?;
 
     let func_code = buf[0];
     let read_count = buf[1];
 
-    if func_code != FUNC_READ_REGISTERS || read_count != MEASUREMENT_REG_COUNT {
+    if func_code != FUNC_READ_REGISTERS || read_count != count {
         return Err(Error::SensorFailed);
     }
 
-    let crc = u16::from_be_bytes([buf[6], buf[7]]);
-    if crc != crc16(&buf[2..6]) {
+    let data = &buf[2..2 + out.len()];
+    let crc = u16::from_be_bytes([buf[2 + out.len()], buf[3 + out.len()]]);
+    if crc != crc16(data) {
         return Err(Error::IncorrectCrc);
     }
 
-    let raw_humidity = u16::from_be_bytes([
-        buf[2 + REG_HUMIDITY_HIGH as usize],
-        buf[2 + REG_HUMIDITY_LOW as usize],
-    ]);
-    let raw_temperature = u16::from_be_bytes([
-        buf[2 + REG_TEMPERATURE_HIGH as usize],
-        buf[2 + REG_TEMPERATURE_LOW as usize],
-    ]);
-
-    Ok(Measurement::from_raw(raw_temperature, raw_humidity))
+    out.copy_from_slice(data);
+    Ok(())
 }
 
 #[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct Measurement {
     temperature: i16,
     humidity: u16,
@@ -78,7 +360,7 @@ pub struct Measurement {
 impl Measurement {
     fn from_raw(raw_temperature: u16, raw_humidity: u16) -> Self {
         let temperature = if raw_temperature & 0x8000 != 0 {
-            (raw_temperature & 0x7FFF) as i16 * -1
+            -((raw_temperature & 0x7FFF) as i16)
         } else {
             raw_temperature as i16
         };
@@ -100,10 +382,20 @@ impl Measurement {
     /// Returns the temperature as an f32.
     ///
     /// The value is in degrees Celsius.
+    #[cfg(feature = "float")]
     pub fn temperature_f32(&self) -> f32 {
         f32::from(self.temperature) * 0.1
     }
 
+    /// Returns the temperature in milli-degrees Celsius, without using any
+    /// floating point math.
+    ///
+    /// The sensor already reports tenths of a degree, so this is exact, not
+    /// an approximation.
+    pub fn temperature_millidegrees(&self) -> i32 {
+        i32::from(self.temperature) * 100
+    }
+
     /// Returns the integer representation of the humidity.
     ///
     /// This is a base 10 fixed point number with 1 digit behind the decimal point.
@@ -115,9 +407,84 @@ impl Measurement {
     /// Returns the humidity as an f32.
     ///
     /// The value is Relative Humidity in range [0, 1].
+    #[cfg(feature = "float")]
     pub fn humidity_f32(&self) -> f32 {
         f32::from(self.humidity) * 0.001
     }
+
+    /// Returns the relative humidity in permille (tenths of a percent),
+    /// without using any floating point math.
+    ///
+    /// This is the raw value reported by the sensor, already in 0.1% units.
+    pub fn humidity_permille(&self) -> u16 {
+        self.humidity
+    }
+
+    /// Returns the dew point in milli-degrees Celsius, derived from the
+    /// temperature and relative humidity via the Magnus-Tetens
+    /// approximation. Returns `None` when the humidity reading is zero, for
+    /// which the approximation is undefined.
+    ///
+    /// This approximation is accurate to within about ±0.35 °C over typical
+    /// ambient conditions. Despite returning an integer, computing it
+    /// requires a natural logarithm, so — unlike [`Measurement::temperature_millidegrees`]
+    /// and [`Measurement::humidity_permille`] — this is not float-free and is
+    /// gated behind the `float` feature, which pulls in `libm` for `no_std`
+    /// targets without hardware log support.
+    #[cfg(feature = "float")]
+    pub fn dew_point_millidegrees(&self) -> Option<i32> {
+        const B: f32 = 17.62;
+        const C: f32 = 243.12;
+
+        if self.humidity == 0 {
+            return None;
+        }
+
+        let temperature = self.temperature_millidegrees() as f32 * 0.001;
+        let relative_humidity = f32::from(self.humidity_permille()) * 0.1;
+
+        let gamma = logf(relative_humidity / 100.0) + (B * temperature) / (C + temperature);
+        let dew_point = (C * gamma) / (B - gamma);
+
+        Some((dew_point * 1000.0) as i32)
+    }
+
+    /// Returns the dew point in degrees Celsius.
+    ///
+    /// See [`Measurement::dew_point_millidegrees`] for the `RH == 0` edge
+    /// case and the accuracy of the underlying approximation.
+    #[cfg(feature = "float")]
+    pub fn dew_point_f32(&self) -> Option<f32> {
+        self.dew_point_millidegrees()
+            .map(|millidegrees| millidegrees as f32 * 0.001)
+    }
+}
+
+/// The sensor's model number, firmware version and unique device ID, as
+/// read from registers 0x08-0x0E.
+#[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DeviceInfo {
+    model: u16,
+    version: u8,
+    device_id: u32,
+}
+
+impl DeviceInfo {
+    /// Returns the sensor's model number.
+    pub fn model(&self) -> u16 {
+        self.model
+    }
+
+    /// Returns the sensor's firmware version.
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// Returns the sensor's unique device ID.
+    pub fn device_id(&self) -> u32 {
+        self.device_id
+    }
 }
 
 fn crc16(data: &[u8]) -> u16 {
@@ -137,3 +504,160 @@ fn crc16(data: &[u8]) -> u16 {
 
     crc
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An I2C stub that asserts the exact command it expects and replies
+    /// with a canned response, standing in for a real AM2320 on the bus.
+    struct MockI2c<'a> {
+        expected_command: &'a [u8],
+        response: &'a [u8],
+    }
+
+    impl i2c::Write for MockI2c<'_> {
+        type Error = ();
+
+        fn write(&mut self, _address: u8, _bytes: &[u8]) -> Result<(), ()> {
+            Ok(())
+        }
+    }
+
+    impl i2c::WriteRead for MockI2c<'_> {
+        type Error = ();
+
+        fn write_read(&mut self, _address: u8, bytes: &[u8], buffer: &mut [u8]) -> Result<(), ()> {
+            assert_eq!(bytes, self.expected_command);
+            buffer.copy_from_slice(self.response);
+            Ok(())
+        }
+    }
+
+    /// A timer stub whose wake-up delay elapses instantly.
+    struct MockTimer;
+
+    impl timer::CountDown for MockTimer {
+        type Time = u32;
+
+        fn start<TM>(&mut self, _count: TM)
+        where
+            TM: Into<u32>,
+        {
+        }
+
+        fn wait(&mut self) -> nb::Result<(), void::Void> {
+            Ok(())
+        }
+    }
+
+    fn response_with_crc(func_code: u8, payload: &[u8], out: &mut [u8]) {
+        out[0] = func_code;
+        out[1] = payload.len() as u8;
+        out[2..2 + payload.len()].copy_from_slice(payload);
+        let crc = crc16(payload).to_be_bytes();
+        out[2 + payload.len()..4 + payload.len()].copy_from_slice(&crc);
+    }
+
+    #[test]
+    fn from_raw_decodes_positive_and_negative_temperature() {
+        let positive = Measurement::from_raw(250, 500);
+        assert_eq!(positive.temperature(), 250);
+        assert_eq!(positive.humidity(), 500);
+
+        // The sign bit (0x8000) flags negative temperatures.
+        let negative = Measurement::from_raw(0x8000 | 50, 500);
+        assert_eq!(negative.temperature(), -50);
+    }
+
+    #[test]
+    fn measure_parses_humidity_and_temperature_registers() {
+        let mut response = [0; 8];
+        response_with_crc(
+            FUNC_READ_REGISTERS,
+            &[0x01, 0xF4, 0x00, 0xC8],
+            &mut response,
+        );
+
+        let mut i2c = MockI2c {
+            expected_command: &[
+                FUNC_READ_REGISTERS,
+                REG_HUMIDITY_HIGH,
+                MEASUREMENT_REG_COUNT,
+            ],
+            response: &response,
+        };
+        let mut timer = MockTimer;
+
+        let measurement = measure(&mut i2c, &mut timer, 0).unwrap();
+        assert_eq!(measurement.humidity(), 500);
+        assert_eq!(measurement.temperature(), 200);
+    }
+
+    #[test]
+    fn device_info_parses_model_version_and_id() {
+        let mut response = [0; 11];
+        response_with_crc(
+            FUNC_READ_REGISTERS,
+            &[0x01, 0x23, 0x02, 0xAA, 0xBB, 0xCC, 0xDD],
+            &mut response,
+        );
+
+        let mut i2c = MockI2c {
+            expected_command: &[FUNC_READ_REGISTERS, REG_MODEL_HIGH, DEVICE_INFO_REG_COUNT],
+            response: &response,
+        };
+        let mut timer = MockTimer;
+
+        let info = device_info(&mut i2c, &mut timer, 0).unwrap();
+        assert_eq!(info.model(), 0x0123);
+        assert_eq!(info.version(), 0x02);
+        assert_eq!(info.device_id(), 0xAABBCCDD);
+    }
+
+    #[test]
+    fn read_registers_rejects_bad_crc() {
+        let mut response = [0; 8];
+        response_with_crc(
+            FUNC_READ_REGISTERS,
+            &[0x01, 0xF4, 0x00, 0xC8],
+            &mut response,
+        );
+        *response.last_mut().unwrap() ^= 0xFF;
+
+        let mut i2c = MockI2c {
+            expected_command: &[
+                FUNC_READ_REGISTERS,
+                REG_HUMIDITY_HIGH,
+                MEASUREMENT_REG_COUNT,
+            ],
+            response: &response,
+        };
+        let mut timer = MockTimer;
+
+        assert!(matches!(
+            measure(&mut i2c, &mut timer, 0),
+            Err(Error::IncorrectCrc)
+        ));
+    }
+
+    #[cfg(feature = "float")]
+    #[test]
+    fn dew_point_millidegrees_matches_known_value() {
+        // 25.0 degC / 50.0 %RH has a well known dew point of ~13.85 degC.
+        let measurement = Measurement::from_raw(250, 500);
+        let dew_point = measurement.dew_point_millidegrees().unwrap();
+        assert!(
+            (dew_point - 13_851).abs() <= 50,
+            "dew point {} too far from expected 13_851",
+            dew_point
+        );
+    }
+
+    #[cfg(feature = "float")]
+    #[test]
+    fn dew_point_millidegrees_is_none_at_zero_humidity() {
+        let measurement = Measurement::from_raw(250, 0);
+        assert_eq!(measurement.dew_point_millidegrees(), None);
+    }
+}